@@ -1,7 +1,8 @@
 use std::f32::consts::PI;
 
 use avian3d::prelude::{
-    Collider, CollisionLayers, RigidBody, Sensor, ShapeHitData, SpatialQuery, SpatialQueryFilter,
+    Collider, CollisionLayers, ExternalImpulse, Mass, RigidBody, Sensor, ShapeCastConfig,
+    ShapeHitData, SpatialQuery, SpatialQueryFilter,
 };
 use bevy::prelude::*;
 use bevy_enhanced_input::prelude::{ActionState, Actions};
@@ -29,11 +30,147 @@ pub struct Character {
     velocity: Vec3,
     floor: Option<Dir3>,
     up: Dir3,
+    /// Slopes steeper than this are treated as a wall: `move_and_slide` deflects against them
+    /// and they are never reported as `floor`.
+    max_climb_angle: f32,
+    /// Slopes steeper than this (but still within `max_climb_angle`) are walkable but not fully
+    /// stable: the character stands on them but slides downhill under gravity.
+    min_slide_angle: f32,
+    /// Opt-in: when set, `move_and_slide` applies an impulse to `RigidBody::Dynamic` bodies the
+    /// character runs into instead of treating them as immovable walls.
+    push_dynamic_bodies: bool,
+    /// Mass used when pushing `RigidBody::Dynamic` bodies out of the way during `move_and_slide`.
+    character_mass: f32,
+    /// Scales the impulse applied to dynamic bodies the character pushes into; lower this to let
+    /// heavy crates resist more, raise it to shove light ones harder.
+    push_force_scale: f32,
+    /// Seconds since `floor` was last `Some`; reset to zero every grounded frame.
+    time_since_grounded: f32,
+    /// How long after leaving the ground a jump is still allowed (coyote time).
+    coyote_time: f32,
+    /// Seconds since a jump was requested while airborne, if one is still buffered.
+    jump_buffer: Option<f32>,
+    /// How long a jump request made while airborne stays buffered, to be consumed on landing.
+    jump_buffer_duration: f32,
+    /// Whether the jump action was held last frame, used to detect release for variable height.
+    jump_held: bool,
+    /// Fraction of upward velocity kept when the jump button is released while still rising.
+    jump_cut_factor: f32,
+    /// Maximum rate, in radians/sec, at which `up` reorients toward the floor normal.
+    up_realign_rate: f32,
+    /// Below this angle to the target normal, `up` is left alone so tiny normal fluctuations
+    /// don't cause jitter.
+    up_realign_deadzone: f32,
+    /// How far to trace down when re-checking for a floor the character just left; `None`
+    /// disables ground snapping entirely.
+    snap_to_ground: Option<SnapToGround>,
+    /// Keep `floor` reporting its last known value for this many frames after ground contact is
+    /// actually lost, so crossing small gaps or seams doesn't flicker the grounded state.
+    grounded_grace_frames: u32,
+    /// Frames since the last frame `move_and_slide`/snapping actually found a floor.
+    frames_since_grounded: u32,
+    /// Frames of ground-snap suppression remaining; set after a jump so the character can
+    /// actually leave the ground instead of being immediately snapped back down.
+    snap_suppress_frames: u32,
+    /// Set when a deep penetration was detected; the controller nudges along this escape
+    /// direction over a few frames instead of snapping out in one step.
+    tunneling: Option<TunnelEscape>,
+}
+
+/// In-progress recovery from a deep overlap with other geometry (see [`Character::tunneling`]).
+#[derive(Clone, Copy)]
+struct TunnelEscape {
+    direction: Dir3,
+    remaining_frames: u32,
+}
+
+/// Ground-snap trace distance, either an absolute world-space distance or a fraction of the
+/// collider height.
+#[derive(Clone, Copy)]
+pub enum SnapToGround {
+    Absolute(f32),
+    Relative(f32),
 }
 impl Character {
     pub fn get_velocity(&self) -> Vec3 {
         self.velocity
     }
+
+    /// Slopes steeper than this are treated as a wall instead of `floor`.
+    pub fn with_max_climb_angle(mut self, max_climb_angle: f32) -> Self {
+        self.max_climb_angle = max_climb_angle;
+        self
+    }
+
+    /// Slopes steeper than this (but still within `max_climb_angle`) are walkable but slide.
+    pub fn with_min_slide_angle(mut self, min_slide_angle: f32) -> Self {
+        self.min_slide_angle = min_slide_angle;
+        self
+    }
+
+    /// Opt in to applying an impulse to `RigidBody::Dynamic` bodies the character runs into,
+    /// instead of treating them as immovable walls.
+    pub fn with_push_dynamic_bodies(mut self, push_dynamic_bodies: bool) -> Self {
+        self.push_dynamic_bodies = push_dynamic_bodies;
+        self
+    }
+
+    /// Mass used when pushing `RigidBody::Dynamic` bodies out of the way during `move_and_slide`.
+    pub fn with_character_mass(mut self, character_mass: f32) -> Self {
+        self.character_mass = character_mass;
+        self
+    }
+
+    /// Scales the impulse applied to dynamic bodies the character pushes into.
+    pub fn with_push_force_scale(mut self, push_force_scale: f32) -> Self {
+        self.push_force_scale = push_force_scale;
+        self
+    }
+
+    /// How long after leaving the ground a jump is still allowed (coyote time).
+    pub fn with_coyote_time(mut self, coyote_time: f32) -> Self {
+        self.coyote_time = coyote_time;
+        self
+    }
+
+    /// How long a jump request made while airborne stays buffered, to be consumed on landing.
+    pub fn with_jump_buffer_duration(mut self, jump_buffer_duration: f32) -> Self {
+        self.jump_buffer_duration = jump_buffer_duration;
+        self
+    }
+
+    /// Fraction of upward velocity kept when the jump button is released while still rising.
+    pub fn with_jump_cut_factor(mut self, jump_cut_factor: f32) -> Self {
+        self.jump_cut_factor = jump_cut_factor;
+        self
+    }
+
+    /// Maximum rate, in radians/sec, at which `up` reorients toward the floor normal.
+    pub fn with_up_realign_rate(mut self, up_realign_rate: f32) -> Self {
+        self.up_realign_rate = up_realign_rate;
+        self
+    }
+
+    /// Below this angle to the target normal, `up` is left alone so tiny normal fluctuations
+    /// don't cause jitter.
+    pub fn with_up_realign_deadzone(mut self, up_realign_deadzone: f32) -> Self {
+        self.up_realign_deadzone = up_realign_deadzone;
+        self
+    }
+
+    /// How far to trace down when re-checking for a floor the character just left; `None`
+    /// disables ground snapping entirely.
+    pub fn with_snap_to_ground(mut self, snap_to_ground: Option<SnapToGround>) -> Self {
+        self.snap_to_ground = snap_to_ground;
+        self
+    }
+
+    /// Keep `floor` reporting its last known value for this many frames after ground contact is
+    /// actually lost, so crossing small gaps or seams doesn't flicker the grounded state.
+    pub fn with_grounded_grace_frames(mut self, grounded_grace_frames: u32) -> Self {
+        self.grounded_grace_frames = grounded_grace_frames;
+        self
+    }
 }
 impl Default for Character {
     fn default() -> Self {
@@ -41,6 +178,28 @@ impl Default for Character {
             velocity: Vec3::ZERO,
             floor: None,
             up: Dir3::Y,
+            max_climb_angle: EXAMPLE_WALKABLE_ANGLE,
+            min_slide_angle: EXAMPLE_SLIDE_ANGLE,
+            push_dynamic_bodies: false,
+            character_mass: EXAMPLE_CHARACTER_MASS,
+            push_force_scale: EXAMPLE_PUSH_FORCE_SCALE,
+            // Start as if we've been airborne forever so coyote time can't fire before the
+            // character has ever touched the ground.
+            time_since_grounded: f32::MAX,
+            coyote_time: EXAMPLE_COYOTE_TIME,
+            jump_buffer: None,
+            jump_buffer_duration: EXAMPLE_JUMP_BUFFER_DURATION,
+            jump_held: false,
+            jump_cut_factor: EXAMPLE_JUMP_CUT_FACTOR,
+            up_realign_rate: EXAMPLE_UP_REALIGN_RATE,
+            up_realign_deadzone: EXAMPLE_UP_REALIGN_DEADZONE,
+            snap_to_ground: Some(SnapToGround::Relative(EXAMPLE_SNAP_TO_GROUND_FRACTION)),
+            grounded_grace_frames: EXAMPLE_GROUNDED_GRACE_FRAMES,
+            // Start as if we've been airborne forever so the grace window can't fire before the
+            // character has ever touched the ground.
+            frames_since_grounded: u32::MAX,
+            snap_suppress_frames: 0,
+            tunneling: None,
         }
     }
 }
@@ -55,8 +214,23 @@ const EXAMPLE_FLOOR_ACCELERATION: f32 = 100.0;
 const EXAMPLE_AIR_ACCELERATION: f32 = 40.0;
 const EXAMPLE_FRICTION: f32 = 60.0;
 const EXAMPLE_WALKABLE_ANGLE: f32 = PI / 4.0;
+const EXAMPLE_SLIDE_ANGLE: f32 = PI / 6.0;
 const EXAMPLE_JUMP_IMPULSE: f32 = 6.0;
 const EXAMPLE_GRAVITY: f32 = 20.0; // realistic earth gravity tend to feel wrong for games
+const EXAMPLE_CHARACTER_MASS: f32 = 80.0;
+const EXAMPLE_PUSH_FORCE_SCALE: f32 = 1.0;
+const EXAMPLE_COYOTE_TIME: f32 = 0.1;
+const EXAMPLE_JUMP_BUFFER_DURATION: f32 = 0.15;
+const EXAMPLE_JUMP_CUT_FACTOR: f32 = 0.5;
+const EXAMPLE_UP_REALIGN_RATE: f32 = PI; // 180 degrees/sec
+const EXAMPLE_UP_REALIGN_DEADZONE: f32 = 0.01;
+const EXAMPLE_SNAP_TO_GROUND_FRACTION: f32 = 0.5;
+const EXAMPLE_GROUNDED_GRACE_FRAMES: u32 = 3;
+const EXAMPLE_JUMP_SNAP_SUPPRESS_FRAMES: u32 = 3;
+const EXAMPLE_MAX_DEPENETRATION_PER_FRAME: f32 = 0.5;
+const EXAMPLE_SEVERE_PENETRATION_DEPTH: f32 = 0.1;
+const EXAMPLE_TUNNEL_ESCAPE_FRAMES: u32 = 5;
+const EXAMPLE_TUNNEL_ESCAPE_DISTANCE_PER_FRAME: f32 = 0.05;
 
 fn movement(
     mut q_kcc: Query<
@@ -72,19 +246,128 @@ fn movement(
     >,
     main_camera: Single<&Transform, (With<MainCamera>, Without<Character>)>,
     sensors: Query<Entity, With<Sensor>>,
+    mut dynamic_bodies: Query<
+        (&RigidBody, &Mass, &GlobalTransform, &mut ExternalImpulse),
+        Without<Character>,
+    >,
     time: Res<Time>,
     spatial_query: SpatialQuery,
 ) {
     let main_camera_transform = main_camera.into_inner();
     for (entity, actions, mut transform, mut character, collider, layers) in &mut q_kcc {
-        if actions.action::<Jump>().state() == ActionState::Fired {
-            if character.floor.is_some() {
-                let impulse = character.up * EXAMPLE_JUMP_IMPULSE;
-                character.velocity += impulse;
-                character.floor = None;
+        // Filter out the character entity as well as any entities not in the character's collision filter
+        let mut filter = SpatialQueryFilter::default()
+            .with_excluded_entities([entity])
+            .with_mask(layers.filters);
+
+        // Also filter out sensor entities
+        filter.excluded_entities.extend(sensors);
+
+        // Depenetration / anti-tunneling: resolve any overlap left over from being pushed by a
+        // moving platform or a fast dynamic body before running the normal accelerate/move_and_slide
+        // cycle, so the character never settles inside static or dynamic geometry.
+        if let Some(tunneling) = character.tunneling.as_mut() {
+            // Already recovering from a deep penetration: keep nudging along the recorded escape
+            // direction over a few frames instead of snapping out violently in one step.
+            transform.translation +=
+                *tunneling.direction * EXAMPLE_TUNNEL_ESCAPE_DISTANCE_PER_FRAME;
+            let into_surface = character.velocity.dot(*tunneling.direction).min(0.0);
+            character.velocity -= *tunneling.direction * into_surface;
+            tunneling.remaining_frames -= 1;
+            if tunneling.remaining_frames == 0 {
+                character.tunneling = None;
+            }
+        } else {
+            let correction = depenetrate(
+                collider,
+                transform.translation,
+                transform.rotation,
+                &spatial_query,
+                &filter,
+            );
+            if correction.length() > EXAMPLE_SEVERE_PENETRATION_DEPTH {
+                if let Ok(direction) = Dir3::new(correction) {
+                    character.tunneling = Some(TunnelEscape {
+                        direction,
+                        remaining_frames: EXAMPLE_TUNNEL_ESCAPE_FRAMES,
+                    });
+                }
+            } else if correction != Vec3::ZERO {
+                transform.translation += correction;
+                if let Ok(direction) = Dir3::new(correction) {
+                    let into_surface = character.velocity.dot(*direction).min(0.0);
+                    character.velocity -= *direction * into_surface;
+                }
             }
         }
 
+        let jump_state = actions.action::<Jump>().state();
+        let jump_held = jump_state != ActionState::None;
+
+        // Coyote time: keep track of how long it's been since we were last grounded so a jump
+        // shortly after walking off a ledge still registers.
+        if character.floor.is_some() {
+            character.time_since_grounded = 0.0;
+        } else {
+            character.time_since_grounded += time.delta_secs();
+        }
+
+        // Jump buffering: remember a jump request made slightly before landing and consume it
+        // the moment the character becomes grounded.
+        if jump_state == ActionState::Fired {
+            character.jump_buffer = Some(0.0);
+        } else if let Some(buffered_for) = character.jump_buffer.as_mut() {
+            *buffered_for += time.delta_secs();
+            if *buffered_for > character.jump_buffer_duration {
+                character.jump_buffer = None;
+            }
+        }
+
+        let can_jump =
+            character.floor.is_some() || character.time_since_grounded < character.coyote_time;
+        let wants_jump = jump_state == ActionState::Fired || character.jump_buffer.is_some();
+
+        if wants_jump && can_jump {
+            let impulse = character.up * EXAMPLE_JUMP_IMPULSE;
+            character.velocity += impulse;
+            character.floor = None;
+            // Consume the coyote window so the same airborne jump can't double-fire.
+            character.time_since_grounded = character.coyote_time;
+            character.jump_buffer = None;
+            // Suppress ground snapping for a few frames so the jump can actually leave the ground.
+            character.snap_suppress_frames = EXAMPLE_JUMP_SNAP_SUPPRESS_FRAMES;
+        }
+
+        // Variable jump height: if the player releases the jump button while still rising, cut
+        // the upward velocity short instead of carrying it to the full arc.
+        if character.jump_held && !jump_held {
+            let up_speed = character.velocity.dot(*character.up);
+            if up_speed > 0.0 {
+                character.velocity -= character.up * up_speed * (1.0 - character.jump_cut_factor);
+            }
+        }
+        character.jump_held = jump_held;
+
+        // Gravity surface alignment: smoothly reorient `up` toward the current floor normal so
+        // the character can run up curved surfaces and eventually walk upside down. We only
+        // re-target while grounded (hysteresis) and slerp with a max angular rate and deadzone
+        // rather than snapping, since naive per-frame snapping jitters on tiny normal changes.
+        if let Some(floor_normal) = character.floor {
+            let angle_to_target = character.up.angle_between(*floor_normal);
+            if angle_to_target > character.up_realign_deadzone {
+                let max_delta = character.up_realign_rate * time.delta_secs();
+                let t = (max_delta / angle_to_target).min(1.0);
+                let rotation_to_target = Quat::from_rotation_arc(*character.up, *floor_normal);
+                let delta_rotation = Quat::IDENTITY.slerp(rotation_to_target, t);
+                character.up = Dir3::new(delta_rotation * *character.up).unwrap_or(character.up);
+                transform.rotation = delta_rotation * transform.rotation;
+            }
+        }
+
+        // Captured after the up-realignment step above so the collision sweeps below run against
+        // the orientation actually applied this frame, not a stale one from before it rotated.
+        let rotation = transform.rotation;
+
         // Get the raw 2D input vector
         let input_vec = actions.action::<input::Move>().value().as_axis2d();
 
@@ -100,15 +383,28 @@ fn movement(
                     character.velocity = apply_friction_result.new_velocity;
                 }
 
-                // Make sure velocity is never towards the floor since this makes the jump height inconsistent
-                let downward_vel = character.velocity.dot(*floor_normal).min(0.0);
-                character.velocity -= floor_normal * downward_vel;
+                let slope_angle = character.up.angle_between(*floor_normal);
+                if slope_angle > character.min_slide_angle {
+                    // Too steep to stand on firmly: slide downhill along the slope instead of
+                    // cancelling the into-floor velocity.
+                    let downhill = (-character.up)
+                        .reject_from_normalized(*floor_normal)
+                        .normalize_or_zero();
+                    character.velocity +=
+                        downhill * EXAMPLE_GRAVITY * slope_angle.sin() * time.delta_secs();
+                } else {
+                    // Make sure velocity is never towards the floor since this makes the jump height inconsistent
+                    let downward_vel = character.velocity.dot(*floor_normal).min(0.0);
+                    character.velocity -= floor_normal * downward_vel;
+                }
 
-                // Project input direction on the floor normal to allow walking down slopes
+                // Project input direction on the smoothed up vector (not the raw floor normal) to
+                // allow walking down slopes, including curved/sloped surfaces where `up` has been
+                // smoothed away from the instantaneous floor normal.
                 // TODO: this is wrong, walking diagonally up/down slopes will be slightly off direction wise,
                 // even more so for steep slopes.
                 direction = direction
-                    .reject_from_normalized(*floor_normal)
+                    .reject_from_normalized(*character.up)
                     .normalize_or_zero();
 
                 EXAMPLE_FLOOR_ACCELERATION
@@ -133,27 +429,22 @@ fn movement(
             character.velocity = accelerate_result.new_velocity;
         }
 
-        let rotation = transform.rotation;
-
-        // Filter out the character entity as well as any entities not in the character's collision filter
-        let mut filter = SpatialQueryFilter::default()
-            .with_excluded_entities([entity])
-            .with_mask(layers.filters);
-
-        // Also filter out sensor entities
-        filter.excluded_entities.extend(sensors);
-
         let config = MoveAndSlideConfig::default();
 
         let up = character.up;
+        let max_climb_angle = character.max_climb_angle;
 
-        // Check if the floor is walkable
+        // Check if the floor is walkable, i.e. not steeper than the character can climb
         let is_walkable = |hit: ShapeHitData| {
             let slope_angle = up.angle_between(hit.normal1);
-            slope_angle < EXAMPLE_WALKABLE_ANGLE
+            slope_angle < max_climb_angle
         };
 
         let mut floor = None;
+        let velocity = character.velocity;
+        let push_dynamic_bodies = character.push_dynamic_bodies;
+        let character_mass = character.character_mass;
+        let push_force_scale = character.push_force_scale;
 
         if let Some(move_and_slide_result) = move_and_slide(
             &spatial_query,
@@ -168,36 +459,124 @@ fn movement(
                 if is_walkable(hit) {
                     floor = Some(Dir3::new(hit.normal1).unwrap());
                 }
+
+                // Push dynamic bodies out of the way instead of treating them as infinite walls.
+                if push_dynamic_bodies {
+                    if let Ok((rigid_body, mass, body_transform, mut impulse)) =
+                        dynamic_bodies.get_mut(hit.entity)
+                    {
+                        if *rigid_body == RigidBody::Dynamic {
+                            let approach_speed = velocity.dot(hit.normal1).min(0.0);
+                            if approach_speed < 0.0 {
+                                let mass_ratio = character_mass / (character_mass + mass.value());
+                                let push = hit.normal1
+                                    * approach_speed
+                                    * character_mass
+                                    * push_force_scale
+                                    * mass_ratio;
+                                impulse.apply_impulse_at_point(
+                                    push,
+                                    hit.point1,
+                                    body_transform.translation(),
+                                );
+                            }
+                        }
+                    }
+                }
             },
         ) {
             transform.translation = move_and_slide_result.new_translation;
             character.velocity = move_and_slide_result.new_velocity;
         }
 
-        // Check for floor when previously on the floor and no floor was found during move and slide
-        // to avoid rapid changes to the grounded state
-        if character.floor.is_some() && floor.is_none() {
-            if let Some((movement, hit)) = character_sweep(
-                collider,
-                config.epsilon,
-                transform.translation,
-                -character.up,
-                10.0, // arbitrary trace distance
-                rotation,
-                &spatial_query,
-                &filter,
-            ) {
-                if is_walkable(hit) {
-                    transform.translation -= character.up * movement; // also snap to the floor
-                    floor = Some(Dir3::new(hit.normal1).unwrap());
+        // Check for floor when previously on the floor (or still within the grounded grace
+        // window) and no floor was found during move and slide, to avoid rapid changes to the
+        // grounded state. Suppressed for a few frames after a jump so the character can actually
+        // leave the ground.
+        let was_grounded = character.floor.is_some()
+            || character.frames_since_grounded < character.grounded_grace_frames;
+        if was_grounded && floor.is_none() && character.snap_suppress_frames == 0 {
+            if let Some(snap_to_ground) = character.snap_to_ground {
+                let trace_distance = match snap_to_ground {
+                    SnapToGround::Absolute(distance) => distance,
+                    SnapToGround::Relative(fraction) => {
+                        let aabb = collider.aabb(Vec3::ZERO, Quat::IDENTITY);
+                        fraction * (aabb.max.y - aabb.min.y)
+                    }
+                };
+
+                if let Some((movement, hit)) = character_sweep(
+                    collider,
+                    config.epsilon,
+                    transform.translation,
+                    -character.up,
+                    trace_distance,
+                    rotation,
+                    &spatial_query,
+                    &filter,
+                ) {
+                    if is_walkable(hit) {
+                        transform.translation -= character.up * movement; // also snap to the floor
+                        floor = Some(Dir3::new(hit.normal1).unwrap());
+                    }
                 }
             }
         }
 
-        character.floor = floor;
+        if character.snap_suppress_frames > 0 {
+            character.snap_suppress_frames -= 1;
+        }
+
+        if floor.is_some() {
+            character.frames_since_grounded = 0;
+            character.floor = floor;
+        } else {
+            character.frames_since_grounded = character.frames_since_grounded.saturating_add(1);
+            if character.frames_since_grounded > character.grounded_grace_frames {
+                character.floor = None;
+            }
+            // else: keep reporting the last known floor through the grace window.
+        }
     }
 }
 
+/// Sweep the collider against its current pose and push it out of anything it's already
+/// overlapping, summing and clamping per-contact corrections to avoid overshooting past multiple
+/// contacts in one frame.
+fn depenetrate(
+    collider: &Collider,
+    translation: Vec3,
+    rotation: Quat,
+    spatial_query: &SpatialQuery,
+    filter: &SpatialQueryFilter,
+) -> Vec3 {
+    let mut correction = Vec3::ZERO;
+
+    let hits = spatial_query.shape_hits(
+        collider,
+        translation,
+        rotation,
+        Dir3::Y, // direction is irrelevant for an overlap query at zero distance
+        8,
+        &ShapeCastConfig {
+            max_distance: 0.0,
+            compute_contact_on_penetration: true,
+            ..default()
+        },
+        filter,
+    );
+
+    for hit in hits {
+        if hit.distance < 0.0 {
+            // `distance` is negative when the shapes are already overlapping; push out along the
+            // contact normal by the penetration depth.
+            correction -= hit.normal1 * hit.distance;
+        }
+    }
+
+    correction.clamp_length_max(EXAMPLE_MAX_DEPENETRATION_PER_FRAME)
+}
+
 pub struct AccelerateResult {
     pub new_velocity: Vec3,
 }